@@ -1,32 +1,190 @@
 use crate::error::Result;
 use hashlink::{LruCache, linked_hash_map::RawEntryMut};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     convert::TryInto,
+    fs,
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 use tproxy_config::IpCidr;
 
-/// A virtual DNS server which allocates IP addresses to clients.
-/// The IP addresses are in the range of private IP addresses.
-/// The DNS server is implemented as a LRU cache.
-pub struct VirtualDns {
-    trailing_dot: bool,
+/// Domains that must bypass virtual-IP allocation and be resolved upstream
+/// instead (captive portals, NTP, local/corporate names, ...).
+///
+/// Patterns may be an exact name (`time.windows.com`), a wildcard suffix
+/// (`*.lan`), or a bare suffix (`.local`); matching is case-insensitive and
+/// ignores a trailing root dot.
+#[derive(Debug, Default, Clone)]
+pub struct FakeIpFilter {
+    exact: HashSet<String>,
+    suffixes: Vec<String>,
+}
+
+impl FakeIpFilter {
+    pub fn new<I, S>(patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut exact = HashSet::new();
+        let mut suffixes = Vec::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref().to_ascii_lowercase();
+            if let Some(suffix) = pattern.strip_prefix("*.") {
+                suffixes.push(format!(".{suffix}"));
+            } else if pattern.starts_with('.') {
+                suffixes.push(pattern);
+            } else {
+                exact.insert(pattern);
+            }
+        }
+        Self { exact, suffixes }
+    }
+
+    /// Returns `true` if `name` should bypass the virtual DNS pool.
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if self.exact.contains(&name) {
+            return true;
+        }
+        self.suffixes
+            .iter()
+            .any(|suffix| name.ends_with(suffix.as_str()) || name == suffix[1..])
+    }
+}
+
+/// A backing store for name↔IP allocations, so that mappings survive a restart.
+///
+/// `load` reports each entry's expiry as a remaining [`Duration`] rather than an
+/// [`Instant`], since an `Instant` from a previous process has no meaning in this
+/// one; `store` takes the absolute `Instant` and leaves the conversion to the store.
+pub trait DnsStore: Send + Sync {
+    fn load(&self) -> Result<Vec<(IpAddr, String, Option<Duration>)>>;
+    fn store(&mut self, ip: IpAddr, name: &str, expiry: Option<Instant>) -> Result<()>;
+    fn delete(&mut self, ip: &IpAddr) -> Result<()>;
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonDnsEntry {
+    ip: IpAddr,
+    name: String,
+    expiry_secs: Option<u64>,
+}
+
+/// A `DnsStore` that keeps the whole mapping table in a single JSON file,
+/// rewriting it on every change. This is simple rather than efficient; it is
+/// meant for the modest number of entries a virtual DNS pool holds.
+pub struct JsonFileDnsStore {
+    path: PathBuf,
+    entries: HashMap<IpAddr, (String, Option<Duration>)>,
+}
+
+impl JsonFileDnsStore {
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let entries = Self::read(&path)?;
+        Ok(Self { path, entries })
+    }
+
+    fn read(path: &Path) -> Result<HashMap<IpAddr, (String, Option<Duration>)>> {
+        if !path.exists() {
+            return Ok(HashMap::default());
+        }
+        let data = fs::read_to_string(path)?;
+        if data.trim().is_empty() {
+            return Ok(HashMap::default());
+        }
+        let records: Vec<JsonDnsEntry> = serde_json::from_str(&data)?;
+        Ok(records
+            .into_iter()
+            .map(|record| (record.ip, (record.name, record.expiry_secs.map(Duration::from_secs))))
+            .collect())
+    }
+
+    fn flush(&self) -> Result<()> {
+        let records: Vec<JsonDnsEntry> = self
+            .entries
+            .iter()
+            .map(|(ip, (name, expiry))| JsonDnsEntry {
+                ip: *ip,
+                name: name.clone(),
+                expiry_secs: expiry.map(|d| d.as_secs()),
+            })
+            .collect();
+        let data = serde_json::to_string_pretty(&records)?;
+        fs::write(&self.path, data)?;
+        Ok(())
+    }
+}
+
+impl DnsStore for JsonFileDnsStore {
+    fn load(&self) -> Result<Vec<(IpAddr, String, Option<Duration>)>> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|(ip, (name, expiry))| (*ip, name.clone(), *expiry))
+            .collect())
+    }
+
+    fn store(&mut self, ip: IpAddr, name: &str, expiry: Option<Instant>) -> Result<()> {
+        let remaining = expiry.map(|at| at.saturating_duration_since(Instant::now()));
+        self.entries.insert(ip, (name.to_owned(), remaining));
+        self.flush()
+    }
+
+    fn delete(&mut self, ip: &IpAddr) -> Result<()> {
+        self.entries.remove(ip);
+        self.flush()
+    }
+}
+
+/// A source of monotonic time, so that lease expiration can be driven by a fake
+/// clock in tests instead of the real one.
+pub trait SystemTimeSource: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The default `SystemTimeSource`, backed by `std::time::Instant::now`.
+#[derive(Default)]
+pub struct StdSystemTime;
+
+impl SystemTimeSource for StdSystemTime {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Which address family a virtual allocation belongs to. A single domain name
+/// can hold one mapping per family at once, so dual-stack clients get a
+/// coherent A and AAAA answer instead of only ever seeing one or the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum AddressFamily {
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    fn of(addr: IpAddr) -> Self {
+        if addr.is_ipv4() { Self::V4 } else { Self::V6 }
+    }
+}
+
+/// One independent allocation pool: a contiguous IP range, an LRU eviction
+/// cache and the cursor used to hand out the next address.
+struct Pool {
     lru_cache: LruCache<IpAddr, NameCacheEntry>,
-    name_to_ip: HashMap<String, IpAddr>,
     network_addr: IpAddr,
     broadcast_addr: IpAddr,
     next_addr: IpAddr,
 }
 
-struct NameCacheEntry {
-    name: String,
-}
-
-impl VirtualDns {
-    pub fn new(ip_pool: IpCidr) -> Self {
-        let network_addr = ip_pool.first_address();
-        let broadcast_addr = ip_pool.last_address();
+impl Pool {
+    fn new(cidr: IpCidr) -> Self {
+        let network_addr = cidr.first_address();
+        let broadcast_addr = cidr.last_address();
         let capacity = match (network_addr, broadcast_addr) {
             (IpAddr::V4(n), IpAddr::V4(b)) => {
                 let n: u32 = n.into();
@@ -41,23 +199,134 @@ impl VirtualDns {
             _ => unreachable!(),
         };
         Self {
-            trailing_dot: false,
-            next_addr: network_addr,
-            name_to_ip: HashMap::default(),
             lru_cache: LruCache::new(capacity),
             network_addr,
             broadcast_addr,
+            next_addr: network_addr,
+        }
+    }
+
+    fn advance_past(&mut self, addr: IpAddr) -> Result<()> {
+        if addr >= self.next_addr {
+            self.next_addr = VirtualDns::increment_ip(addr)?;
+            self.clamp_next_addr();
+        }
+        Ok(())
+    }
+
+    fn clamp_next_addr(&mut self) {
+        if self.next_addr > self.broadcast_addr || self.next_addr < self.network_addr {
+            self.next_addr = self.network_addr;
+        }
+    }
+}
+
+/// A virtual DNS server which allocates IP addresses to clients.
+/// The IP addresses are in the range of private IP addresses.
+/// The DNS server is implemented as a LRU cache.
+pub struct VirtualDns {
+    trailing_dot: bool,
+    v4: Pool,
+    v6: Option<Pool>,
+    name_to_ip: HashMap<(String, AddressFamily), IpAddr>,
+    lease_duration: Duration,
+    time_source: Box<dyn SystemTimeSource>,
+    store: Option<Box<dyn DnsStore>>,
+    fake_ip_filter: FakeIpFilter,
+}
+
+struct NameCacheEntry {
+    name: String,
+    expires_at: Instant,
+}
+
+/// The outcome of handling an incoming DNS query.
+pub enum DnsAnswer {
+    /// A synthesized response carrying a freshly allocated or cached virtual IP.
+    Resolved { response: Vec<u8>, name: String, ip: IpAddr },
+    /// `name` matched the fake-IP filter, or is a PTR query that does not
+    /// name an allocated virtual IP; forward the original query upstream
+    /// instead of synthesizing a response for it.
+    Bypass { name: String },
+}
+
+impl VirtualDns {
+    pub fn new(
+        ip_pool: IpCidr,
+        ipv6_pool: Option<IpCidr>,
+        lease_duration: Duration,
+        time_source: Box<dyn SystemTimeSource>,
+        store: Option<Box<dyn DnsStore>>,
+        fake_ip_filter: FakeIpFilter,
+    ) -> Result<Self> {
+        let mut virtual_dns = Self {
+            trailing_dot: false,
+            v4: Pool::new(ip_pool),
+            v6: ipv6_pool.map(Pool::new),
+            name_to_ip: HashMap::default(),
+            lease_duration,
+            time_source,
+            store,
+            fake_ip_filter,
+        };
+
+        let restored = match &virtual_dns.store {
+            Some(store) => store.load()?,
+            None => Vec::new(),
+        };
+        let now = virtual_dns.time_source.now();
+        for (ip, name, remaining) in restored {
+            let family = AddressFamily::of(ip);
+            let pool = match family {
+                AddressFamily::V4 => Some(&mut virtual_dns.v4),
+                AddressFamily::V6 => virtual_dns.v6.as_mut(),
+            };
+            let Some(pool) = pool else { continue };
+            let expires_at = now + remaining.unwrap_or(virtual_dns.lease_duration);
+            pool.lru_cache.insert(ip, NameCacheEntry { name: name.clone(), expires_at });
+            virtual_dns.name_to_ip.insert((name, family), ip);
+            pool.advance_past(ip)?;
         }
+        Ok(virtual_dns)
     }
 
-    /// Returns the DNS response to send back to the client.
-    pub fn generate_query(&mut self, data: &[u8]) -> Result<(Vec<u8>, String, IpAddr)> {
-        use crate::dns;
+    /// Returns the DNS response to send back to the client, or a `Bypass`
+    /// signal if `qname` is covered by the fake-IP filter (or is a reverse
+    /// lookup we can't answer) and must instead be forwarded to an upstream
+    /// resolver.
+    pub fn generate_query(&mut self, data: &[u8]) -> Result<DnsAnswer> {
+        use crate::dns::{self, RecordType};
         let message = dns::parse_data_to_dns_message(data, false)?;
         let qname = dns::extract_domain_from_dns_message(&message)?;
-        let ip = self.find_or_allocate_ip(qname.clone())?;
+        let qtype = dns::extract_query_type_from_dns_message(&message)?;
+
+        if qtype == RecordType::PTR {
+            if let Some(addr) = parse_ptr_name(&qname)
+                && let Some(name) = self.resolve_ip(&addr)
+            {
+                let name = name.clone();
+                let message = dns::build_dns_ptr_response(message, &qname, &name, 5)?;
+                return Ok(DnsAnswer::Resolved {
+                    response: message.to_vec()?,
+                    name,
+                    ip: addr,
+                });
+            }
+            return Ok(DnsAnswer::Bypass { name: qname });
+        }
+
+        if self.fake_ip_filter.matches(&qname) {
+            return Ok(DnsAnswer::Bypass { name: qname });
+        }
+
+        let family = if qtype == RecordType::AAAA { AddressFamily::V6 } else { AddressFamily::V4 };
+        let ip = self.find_or_allocate_ip(qname.clone(), family)?;
         let message = dns::build_dns_response(message, &qname, ip, 5)?;
-        Ok((message.to_vec()?, qname, ip))
+        Ok(DnsAnswer::Resolved {
+            response: message.to_vec()?,
+            name: qname,
+            ip,
+        })
     }
 
     fn increment_ip(addr: IpAddr) -> Result<IpAddr> {
@@ -87,18 +356,107 @@ impl VirtualDns {
         Ok(addr)
     }
 
+    /// Returns the pool that would own `addr`, based on its address family.
+    /// Unlike `pool_mut`, this never fails: an IPv6 address when no `v6` pool
+    /// is configured simply has no owning pool.
+    fn pool_mut_for_addr(&mut self, addr: &IpAddr) -> Option<&mut Pool> {
+        match AddressFamily::of(*addr) {
+            AddressFamily::V4 => Some(&mut self.v4),
+            AddressFamily::V6 => self.v6.as_mut(),
+        }
+    }
+
     // This is to be called whenever we receive or send a packet on the socket
     // which connects the tun interface to the client, so existing IP address to name
     // mappings to not expire as long as the connection is active.
+    //
+    // This renewal is in-memory only and is not mirrored through `persist_store`:
+    // persisting on every touched packet would turn the store into a write-on-every-packet
+    // log, defeating the point of a best-effort durability feature. The tradeoff is that a
+    // long-lived, actively-renewed lease's on-disk record doesn't reflect the renewal, so a
+    // restart gives it a fresh `lease_duration` rather than resuming from its real expiry.
     pub fn touch_ip(&mut self, addr: &IpAddr) {
-        let _ = self.lru_cache.get(addr);
+        let lease_expiry = self.lease_expiry();
+        if let Some(entry) = self.pool_mut_for_addr(addr).and_then(|pool| pool.lru_cache.get_mut(addr)) {
+            entry.expires_at = lease_expiry;
+        }
     }
 
+    /// Resolves a virtual IP back to the domain name it was allocated for,
+    /// regardless of which address family it belongs to.
+    ///
+    /// Like `touch_ip`, this renews the in-memory lease without persisting the
+    /// renewal; see `touch_ip` for why.
     pub fn resolve_ip(&mut self, addr: &IpAddr) -> Option<&String> {
-        self.lru_cache.get(addr).map(|entry| &entry.name)
+        let lease_expiry = self.lease_expiry();
+        let entry = self.pool_mut_for_addr(addr)?.lru_cache.get_mut(addr)?;
+        entry.expires_at = lease_expiry;
+        Some(&entry.name)
     }
 
-    fn find_or_allocate_ip(&mut self, name: String) -> Result<IpAddr> {
+    fn lease_expiry(&self) -> Instant {
+        self.time_source.now() + self.lease_duration
+    }
+
+    fn pool_mut(&mut self, family: AddressFamily) -> Result<&mut Pool> {
+        match family {
+            AddressFamily::V4 => Ok(&mut self.v4),
+            AddressFamily::V6 => self.v6.as_mut().ok_or_else(|| "No IPv6 virtual pool configured for AAAA queries".into()),
+        }
+    }
+
+    /// Persists an allocation through the configured `DnsStore`, if any.
+    /// Persistence is a best-effort durability feature, not a correctness
+    /// dependency: the in-memory allocation is already valid, so a write
+    /// failure is logged and otherwise ignored rather than failing the
+    /// in-flight query.
+    fn persist_store(&mut self, ip: IpAddr, name: &str, expiry: Option<Instant>) {
+        if let Some(store) = self.store.as_mut()
+            && let Err(err) = store.store(ip, name, expiry)
+        {
+            tracing::warn!("failed to persist DNS allocation for {name} ({ip}): {err}");
+        }
+    }
+
+    /// Persists an eviction through the configured `DnsStore`, if any. See
+    /// `persist_store` for why failures here are logged, not propagated.
+    fn persist_delete(&mut self, ip: &IpAddr) {
+        if let Some(store) = self.store.as_mut()
+            && let Err(err) = store.delete(ip)
+        {
+            tracing::warn!("failed to persist DNS eviction of {ip}: {err}");
+        }
+    }
+
+    /// Evicts every mapping whose lease has expired, freeing its IP for reuse.
+    /// Callers may run this periodically; it is also run implicitly before
+    /// allocating a new IP.
+    pub fn purge_expired(&mut self) -> Result<()> {
+        let now = self.time_source.now();
+        for family in [AddressFamily::V4, AddressFamily::V6] {
+            let Ok(pool) = self.pool_mut(family) else { continue };
+            let expired: Vec<IpAddr> = pool
+                .lru_cache
+                .iter()
+                .filter(|(_, entry)| entry.expires_at <= now)
+                .map(|(ip, _)| *ip)
+                .collect();
+            for ip in expired {
+                let Ok(pool) = self.pool_mut(family) else { continue };
+                if let Some(entry) = pool.lru_cache.remove(&ip) {
+                    self.name_to_ip.remove(&(entry.name, family));
+                    self.persist_delete(&ip);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds or allocates the virtual IP for `name` in the given address
+    /// family. A name can hold one allocation per family at once, so a
+    /// dual-stack client gets a consistent A and AAAA answer for the same
+    /// domain.
+    fn find_or_allocate_ip(&mut self, name: String, family: AddressFamily) -> Result<IpAddr> {
         // This function is a search and creation function.
         // Thus, it is sufficient to canonicalize the name here.
         let insert_name = if name.ends_with('.') && !self.trailing_dot {
@@ -108,56 +466,85 @@ impl VirtualDns {
         };
 
         // Return the IP if it is stored inside our name_to_ip map.
-        if let Some(&ip) = self.name_to_ip.get(&insert_name) {
-            self.lru_cache.get(&ip);
+        if let Some(&ip) = self.name_to_ip.get(&(insert_name.clone(), family)) {
+            let lease_expiry = self.lease_expiry();
+            if let Some(entry) = self.pool_mut(family)?.lru_cache.get_mut(&ip) {
+                entry.expires_at = lease_expiry;
+            }
             return Ok(ip);
         }
 
+        // Reclaim any entries whose lease has expired before deciding whether
+        // we are at capacity, so an expired domain doesn't pin a pool slot.
+        self.purge_expired()?;
+        let lease_expiry = self.lease_expiry();
+        let pool = self.pool_mut(family)?;
+
         // Check if we are at capacity.
-        if self.lru_cache.len() == self.lru_cache.capacity() {
+        if pool.lru_cache.len() == pool.lru_cache.capacity() {
             // Full, evict the LRU entry.
-            if let Some((old_ip, old_entry)) = self.lru_cache.remove_lru() {
-                self.name_to_ip.remove(&old_entry.name);
+            if let Some((old_ip, old_entry)) = pool.lru_cache.remove_lru() {
+                self.name_to_ip.remove(&(old_entry.name, family));
                 let name_clone = insert_name.clone();
-                self.lru_cache.insert(old_ip, NameCacheEntry { name: insert_name });
-                self.name_to_ip.insert(name_clone, old_ip);
-                self.next_addr = Self::increment_ip(old_ip)?;
-                if self.next_addr > self.broadcast_addr || self.next_addr < self.network_addr {
-                    self.next_addr = self.network_addr;
-                }
+                let pool = self.pool_mut(family)?;
+                pool.lru_cache.insert(
+                    old_ip,
+                    NameCacheEntry {
+                        name: insert_name,
+                        expires_at: lease_expiry,
+                    },
+                );
+                self.name_to_ip.insert((name_clone.clone(), family), old_ip);
+                self.persist_store(old_ip, &name_clone, Some(lease_expiry));
+                let pool = self.pool_mut(family)?;
+                pool.next_addr = Self::increment_ip(old_ip)?;
+                pool.clamp_next_addr();
                 return Ok(old_ip);
             }
         }
 
         // Otherwise, find a vacant IP in the pool.
-        let started_at = self.next_addr;
+        let started_at = pool.next_addr;
         loop {
-            if let RawEntryMut::Vacant(vacant) = self.lru_cache.raw_entry_mut().from_key(&self.next_addr) {
+            let pool = self.pool_mut(family)?;
+            if let RawEntryMut::Vacant(vacant) = pool.lru_cache.raw_entry_mut().from_key(&pool.next_addr) {
                 let name_clone = insert_name.clone();
-                vacant.insert(self.next_addr, NameCacheEntry { name: insert_name });
-                self.name_to_ip.insert(name_clone, self.next_addr);
-                let allocated = self.next_addr;
-                self.next_addr = Self::increment_ip(self.next_addr)?;
-                if self.next_addr > self.broadcast_addr || self.next_addr < self.network_addr {
-                    self.next_addr = self.network_addr;
-                }
+                let allocated = pool.next_addr;
+                vacant.insert(
+                    allocated,
+                    NameCacheEntry {
+                        name: insert_name,
+                        expires_at: lease_expiry,
+                    },
+                );
+                self.name_to_ip.insert((name_clone.clone(), family), allocated);
+                self.persist_store(allocated, &name_clone, Some(lease_expiry));
+                let pool = self.pool_mut(family)?;
+                pool.next_addr = Self::increment_ip(allocated)?;
+                pool.clamp_next_addr();
                 return Ok(allocated);
             }
-            self.next_addr = Self::increment_ip(self.next_addr)?;
-            if self.next_addr > self.broadcast_addr || self.next_addr < self.network_addr {
-                self.next_addr = self.network_addr;
-            }
-            if self.next_addr == started_at {
+            pool.next_addr = Self::increment_ip(pool.next_addr)?;
+            pool.clamp_next_addr();
+            if pool.next_addr == started_at {
                 // If we've looped back, treat as full and evict LRU.
-                if let Some((old_ip, old_entry)) = self.lru_cache.remove_lru() {
-                    self.name_to_ip.remove(&old_entry.name);
+                let pool = self.pool_mut(family)?;
+                if let Some((old_ip, old_entry)) = pool.lru_cache.remove_lru() {
+                    self.name_to_ip.remove(&(old_entry.name, family));
                     let name_clone = insert_name.clone();
-                    self.lru_cache.insert(old_ip, NameCacheEntry { name: insert_name });
-                    self.name_to_ip.insert(name_clone, old_ip);
-                    self.next_addr = Self::increment_ip(old_ip)?;
-                    if self.next_addr > self.broadcast_addr || self.next_addr < self.network_addr {
-                        self.next_addr = self.network_addr;
-                    }
+                    let pool = self.pool_mut(family)?;
+                    pool.lru_cache.insert(
+                        old_ip,
+                        NameCacheEntry {
+                            name: insert_name,
+                            expires_at: lease_expiry,
+                        },
+                    );
+                    self.name_to_ip.insert((name_clone.clone(), family), old_ip);
+                    self.persist_store(old_ip, &name_clone, Some(lease_expiry));
+                    let pool = self.pool_mut(family)?;
+                    pool.next_addr = Self::increment_ip(old_ip)?;
+                    pool.clamp_next_addr();
                     return Ok(old_ip);
                 } else {
                     return Err("Virtual IP space for DNS exhausted".into());
@@ -166,3 +553,311 @@ impl VirtualDns {
         }
     }
 }
+
+/// Parses a PTR query name such as `4.3.2.1.in-addr.arpa.` or the IPv6
+/// nibble form ending in `.ip6.arpa.` back into the address it names.
+/// Returns `None` if `qname` isn't a well-formed reverse-lookup name.
+fn parse_ptr_name(qname: &str) -> Option<IpAddr> {
+    let qname = qname.trim_end_matches('.');
+    if let Some(labels) = qname.strip_suffix(".in-addr.arpa") {
+        let parts: Vec<&str> = labels.split('.').collect();
+        if parts.len() != 4 {
+            return None;
+        }
+        let mut octets = [0u8; 4];
+        for (i, part) in parts.iter().enumerate() {
+            octets[3 - i] = part.parse().ok()?;
+        }
+        return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+    }
+    if let Some(labels) = qname.strip_suffix(".ip6.arpa") {
+        let nibbles: Vec<&str> = labels.split('.').collect();
+        if nibbles.len() != 32 {
+            return None;
+        }
+        let mut bytes = [0u8; 16];
+        for (i, nibble) in nibbles.iter().enumerate() {
+            let value = u8::from_str_radix(nibble, 16).ok()?;
+            let k = 31 - i;
+            if k % 2 == 0 {
+                bytes[k / 2] |= value << 4;
+            } else {
+                bytes[k / 2] |= value;
+            }
+        }
+        return Some(IpAddr::V6(Ipv6Addr::from(bytes)));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A `SystemTimeSource` whose clock only moves when a test tells it to,
+    /// so lease expiry can be exercised deterministically.
+    struct FakeTimeSource {
+        now: Mutex<Instant>,
+    }
+
+    impl FakeTimeSource {
+        fn new() -> Self {
+            Self { now: Mutex::new(Instant::now()) }
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.now.lock().unwrap() += by;
+        }
+    }
+
+    impl SystemTimeSource for Arc<FakeTimeSource> {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    /// A `/30` pool (4 usable addresses) paired with the fake clock driving it.
+    fn new_test_dns(lease_duration: Duration) -> (VirtualDns, Arc<FakeTimeSource>) {
+        let clock = Arc::new(FakeTimeSource::new());
+        let cidr = IpCidr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 30);
+        let dns = VirtualDns::new(
+            cidr,
+            None,
+            lease_duration,
+            Box::new(Arc::clone(&clock)),
+            None,
+            FakeIpFilter::default(),
+        )
+        .unwrap();
+        (dns, clock)
+    }
+
+    #[test]
+    fn expired_lease_frees_its_slot_for_reuse() {
+        let (mut dns, clock) = new_test_dns(Duration::from_secs(10));
+        let first_ip = dns.find_or_allocate_ip("a.com".into(), AddressFamily::V4).unwrap();
+        for name in ["b.com", "c.com", "d.com"] {
+            dns.find_or_allocate_ip(name.into(), AddressFamily::V4).unwrap();
+        }
+
+        // All four leases were granted at the same instant; move past all of them.
+        clock.advance(Duration::from_secs(11));
+
+        let reused = dns.find_or_allocate_ip("e.com".into(), AddressFamily::V4).unwrap();
+        assert_eq!(reused, first_ip, "expired entry's IP should be reclaimed before wraparound eviction");
+        assert!(!dns.name_to_ip.contains_key(&("a.com".to_string(), AddressFamily::V4)));
+    }
+
+    #[test]
+    fn touch_ip_renews_the_lease() {
+        let (mut dns, clock) = new_test_dns(Duration::from_secs(10));
+        let ip = dns.find_or_allocate_ip("a.com".into(), AddressFamily::V4).unwrap();
+
+        clock.advance(Duration::from_secs(8));
+        dns.touch_ip(&ip);
+
+        // Without the touch this would have expired at t=10; the renewal pushes
+        // expiry to t=18, so the mapping must survive past the original deadline.
+        clock.advance(Duration::from_secs(7));
+        dns.purge_expired().unwrap();
+        assert!(dns.name_to_ip.contains_key(&("a.com".to_string(), AddressFamily::V4)));
+
+        clock.advance(Duration::from_secs(5));
+        dns.purge_expired().unwrap();
+        assert!(!dns.name_to_ip.contains_key(&("a.com".to_string(), AddressFamily::V4)));
+    }
+
+    #[test]
+    fn resolve_ip_renews_the_lease() {
+        let (mut dns, clock) = new_test_dns(Duration::from_secs(10));
+        let ip = dns.find_or_allocate_ip("a.com".into(), AddressFamily::V4).unwrap();
+
+        clock.advance(Duration::from_secs(8));
+        assert_eq!(dns.resolve_ip(&ip), Some(&"a.com".to_string()));
+
+        clock.advance(Duration::from_secs(7));
+        dns.purge_expired().unwrap();
+        assert!(dns.name_to_ip.contains_key(&("a.com".to_string(), AddressFamily::V4)));
+    }
+
+    #[test]
+    fn full_pool_evicts_the_least_recently_used_entry() {
+        // A lease long enough that nothing expires; only capacity pressure forces eviction.
+        let (mut dns, _clock) = new_test_dns(Duration::from_secs(1000));
+        let first_ip = dns.find_or_allocate_ip("a.com".into(), AddressFamily::V4).unwrap();
+        for name in ["b.com", "c.com", "d.com"] {
+            dns.find_or_allocate_ip(name.into(), AddressFamily::V4).unwrap();
+        }
+
+        // "a.com" was inserted first and never touched again, so it's the LRU entry.
+        let evicted_reuse = dns.find_or_allocate_ip("e.com".into(), AddressFamily::V4).unwrap();
+        assert_eq!(evicted_reuse, first_ip);
+        assert!(!dns.name_to_ip.contains_key(&("a.com".to_string(), AddressFamily::V4)));
+        assert!(dns.name_to_ip.contains_key(&("e.com".to_string(), AddressFamily::V4)));
+    }
+
+    #[test]
+    fn parse_ptr_name_roundtrips_ipv4() {
+        let addr = Ipv4Addr::new(192, 0, 2, 7);
+        let qname = "7.2.0.192.in-addr.arpa.";
+        assert_eq!(parse_ptr_name(qname), Some(IpAddr::V4(addr)));
+    }
+
+    #[test]
+    fn parse_ptr_name_roundtrips_ipv6() {
+        let addr = Ipv6Addr::new(0x2001, 0x0db8, 0, 0, 0, 0, 0, 0x0001);
+        // Nibbles of the address, least-significant first, separated by dots and followed by ".ip6.arpa.".
+        let qname = "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa.";
+        assert_eq!(parse_ptr_name(qname), Some(IpAddr::V6(addr)));
+    }
+
+    #[test]
+    fn parse_ptr_name_rejects_wrong_label_count() {
+        assert_eq!(parse_ptr_name("2.0.192.in-addr.arpa."), None);
+        assert_eq!(parse_ptr_name("1.0.0.8.b.d.0.1.0.0.2.ip6.arpa."), None);
+    }
+
+    #[test]
+    fn parse_ptr_name_rejects_unrelated_suffix() {
+        assert_eq!(parse_ptr_name("a.com."), None);
+    }
+
+    /// A `/126` IPv6 pool (4 usable addresses) alongside the v4 pool from `new_test_dns`.
+    fn new_test_dns_dual_stack(lease_duration: Duration) -> (VirtualDns, Arc<FakeTimeSource>) {
+        let clock = Arc::new(FakeTimeSource::new());
+        let v4_cidr = IpCidr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 30);
+        let v6_cidr = IpCidr::new(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0)), 126);
+        let dns = VirtualDns::new(
+            v4_cidr,
+            Some(v6_cidr),
+            lease_duration,
+            Box::new(Arc::clone(&clock)),
+            None,
+            FakeIpFilter::default(),
+        )
+        .unwrap();
+        (dns, clock)
+    }
+
+    #[test]
+    fn ptr_lookup_resolves_an_allocated_aaaa_name() {
+        let (mut dns, _clock) = new_test_dns_dual_stack(Duration::from_secs(10));
+        let ip = dns.find_or_allocate_ip("a.com".into(), AddressFamily::V6).unwrap();
+        let IpAddr::V6(v6) = ip else { panic!("expected an IPv6 allocation") };
+
+        // Build the reverse-lookup name the way a real AAAA PTR query would carry it,
+        // then confirm `parse_ptr_name` and `resolve_ip` compose to recover the name.
+        let nibbles: Vec<String> = v6
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|byte| [format!("{:x}", byte & 0xf), format!("{:x}", byte >> 4)])
+            .collect();
+        let qname = format!("{}.ip6.arpa.", nibbles.join("."));
+
+        let parsed = parse_ptr_name(&qname).unwrap();
+        assert_eq!(parsed, ip);
+        assert_eq!(dns.resolve_ip(&parsed), Some(&"a.com".to_string()));
+    }
+
+    #[test]
+    fn fake_ip_filter_matches() {
+        let filter = FakeIpFilter::new(["time.windows.com", "*.lan", ".local"]);
+        let cases = [
+            ("time.windows.com", true),
+            ("TIME.WINDOWS.COM", true),
+            ("time.windows.com.", true),
+            ("a.lan", true),
+            ("lan", true),
+            ("sub.a.lan", true),
+            ("evillan.com", false),
+            ("router.local", true),
+            ("local", true),
+            ("example.com", false),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(filter.matches(name), expected, "matches({name:?})");
+        }
+    }
+
+    /// A path inside the system temp dir that's cleaned up when the guard drops,
+    /// so each `JsonFileDnsStore` test gets its own file without leaking one.
+    struct TempJsonPath(PathBuf);
+
+    impl TempJsonPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("virtual_dns_test_{name}_{:?}.json", std::thread::current().id()));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempJsonPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn json_file_dns_store_missing_file_loads_empty() {
+        let path = TempJsonPath::new("missing");
+        let store = JsonFileDnsStore::new(&path.0).unwrap();
+        assert!(store.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn json_file_dns_store_empty_file_loads_empty() {
+        let path = TempJsonPath::new("empty");
+        fs::write(&path.0, "").unwrap();
+        let store = JsonFileDnsStore::new(&path.0).unwrap();
+        assert!(store.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn json_file_dns_store_round_trips_through_a_flush_and_reload() {
+        let path = TempJsonPath::new("round_trip");
+        let ip: IpAddr = Ipv4Addr::new(10, 0, 0, 1).into();
+        let expiry = Instant::now() + Duration::from_secs(30);
+
+        let mut store = JsonFileDnsStore::new(&path.0).unwrap();
+        store.store(ip, "a.com", Some(expiry)).unwrap();
+
+        let reloaded = JsonFileDnsStore::new(&path.0).unwrap();
+        let entries = reloaded.load().unwrap();
+        assert_eq!(entries.len(), 1);
+        let (loaded_ip, loaded_name, remaining) = &entries[0];
+        assert_eq!(*loaded_ip, ip);
+        assert_eq!(loaded_name, "a.com");
+        // The store records a remaining Duration, not the original Instant, so we can
+        // only check it's in the right ballpark rather than bit-for-bit equal.
+        let remaining = remaining.expect("expiry should have been persisted");
+        assert!(remaining <= Duration::from_secs(30) && remaining > Duration::from_secs(25));
+    }
+
+    #[test]
+    fn json_file_dns_store_delete_removes_the_entry() {
+        let path = TempJsonPath::new("delete");
+        let ip: IpAddr = Ipv4Addr::new(10, 0, 0, 2).into();
+
+        let mut store = JsonFileDnsStore::new(&path.0).unwrap();
+        store.store(ip, "a.com", None).unwrap();
+        store.delete(&ip).unwrap();
+
+        let reloaded = JsonFileDnsStore::new(&path.0).unwrap();
+        assert!(reloaded.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn json_file_dns_store_no_expiry_round_trips_as_none() {
+        let path = TempJsonPath::new("no_expiry");
+        let ip: IpAddr = Ipv4Addr::new(10, 0, 0, 3).into();
+
+        let mut store = JsonFileDnsStore::new(&path.0).unwrap();
+        store.store(ip, "a.com", None).unwrap();
+
+        let reloaded = JsonFileDnsStore::new(&path.0).unwrap();
+        let entries = reloaded.load().unwrap();
+        assert_eq!(entries[0].2, None);
+    }
+}